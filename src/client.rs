@@ -1,10 +1,13 @@
 use anyhow::Context;
 use futures_util::future::FutureExt;
 use rand::seq::SliceRandom;
+use rand::Rng;
 use std::str::FromStr;
 use tokio::stream::StreamExt;
 use url::Url;
 
+type SendRequest = hyper::client::conn::SendRequest<hyper::Body>;
+
 #[derive(Debug, Clone)]
 /// a result for a request
 pub struct RequestResult {
@@ -14,8 +17,68 @@ pub struct RequestResult {
     pub end: std::time::Instant,
     /// HTTP status
     pub status: http::StatusCode,
-    /// Length of body
+    /// Length of body on the wire, before decompression.
     pub len_bytes: usize,
+    /// Length of body after decompression, when the response was encoded with
+    /// one of the accepted `Encoding`s. `None` when the body wasn't decoded.
+    pub decompressed_bytes: Option<usize>,
+    /// When this request was scheduled to start by the pacer, as opposed to
+    /// when a worker actually became free to send it. `None` outside paced
+    /// (`*_with_qps`) runs. Used to compute coordinated-omission-corrected
+    /// latency: `end - intended_start`.
+    pub intended_start: Option<std::time::Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A content-coding oha can ask the server for and decode on the way back.
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn from_header_value(value: &str) -> Option<Encoding> {
+        match value.trim() {
+            "gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    async fn decode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        use tokio::io::AsyncReadExt;
+
+        let reader = tokio::io::BufReader::new(body);
+        let mut out = Vec::new();
+        match self {
+            Encoding::Gzip => {
+                async_compression::tokio_02::bufread::GzipDecoder::new(reader)
+                    .read_to_end(&mut out)
+                    .await?;
+            }
+            Encoding::Brotli => {
+                async_compression::tokio_02::bufread::BrotliDecoder::new(reader)
+                    .read_to_end(&mut out)
+                    .await?;
+            }
+            Encoding::Deflate => {
+                async_compression::tokio_02::bufread::DeflateDecoder::new(reader)
+                    .read_to_end(&mut out)
+                    .await?;
+            }
+        }
+        Ok(out)
+    }
 }
 
 impl RequestResult {
@@ -23,6 +86,141 @@ impl RequestResult {
     pub fn duration(&self) -> std::time::Duration {
         self.end - self.start
     }
+
+    /// Latency corrected for the time this request spent queued behind a busy
+    /// worker: `end - intended_start`. `None` outside paced runs, where there
+    /// is no intended start to correct against.
+    pub fn corrected_duration(&self) -> Option<std::time::Duration> {
+        self.intended_start
+            .map(|intended_start| self.end - intended_start)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How inter-arrival gaps between paced (`*_with_qps`) requests are drawn.
+pub enum ArrivalDistribution {
+    /// Requests are scheduled on a fixed `1/qps` grid.
+    Fixed,
+    /// Requests arrive as a Poisson process: gaps are drawn from an exponential
+    /// distribution with rate `qps`, producing the bursty arrival pattern real
+    /// clients exhibit rather than a perfectly even grid.
+    Poisson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which HTTP version to speak on a freshly established connection.
+pub enum HttpVersion {
+    /// Always use HTTP/1.1.
+    Http1,
+    /// Always use HTTP/2 (assumes prior knowledge, no ALPN negotiation required).
+    Http2,
+    /// Negotiate via ALPN on TLS connections, falling back to HTTP/1.1 on plain TCP.
+    Auto,
+}
+
+#[derive(Debug, Clone)]
+/// Where to source trusted roots for certificate verification.
+pub enum RootCertStore {
+    /// Trust the platform's native certificate store.
+    Native,
+    /// Trust Mozilla's curated webpki roots, bundled at compile time.
+    WebPki,
+    /// Trust exactly the certificates found in this PEM file.
+    File(std::path::PathBuf),
+}
+
+/// A certificate verifier that accepts anything, for talking to servers with
+/// self-signed certificates (e.g. local test fixtures).
+struct NoCertificateVerification;
+
+impl rustls::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Transport used to talk to the configured nameservers.
+pub enum ResolverProtocol {
+    Udp,
+    Tcp,
+    /// DNS-over-HTTPS (RFC 8484): queries are wrapped in HTTP/2 POSTs to
+    /// `https://<nameserver>/dns-query`.
+    Https,
+}
+
+#[derive(Debug, Clone)]
+/// How `Client::lookup_ip` resolves the target host.
+pub struct ResolverConfig {
+    pub protocol: ResolverProtocol,
+    /// Explicit upstream nameservers. Required for `Tcp` and `Https`; when empty
+    /// with `Udp`, `trust_dns_resolver::config::ResolverConfig::default()` is used.
+    pub nameservers: Vec<std::net::SocketAddr>,
+    /// TLS name presented by the nameserver, used to validate its certificate
+    /// when `protocol` is `Https`.
+    pub tls_dns_name: Option<String>,
+    /// Re-resolve on every retry instead of caching the result for the worker's
+    /// lifetime, so the resolver itself sits on the measured path.
+    pub refresh_on_retry: bool,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            protocol: ResolverProtocol::Udp,
+            nameservers: Vec::new(),
+            tls_dns_name: None,
+            refresh_on_retry: false,
+        }
+    }
+}
+
+impl ResolverConfig {
+    fn to_trust_dns(
+        &self,
+    ) -> anyhow::Result<(
+        trust_dns_resolver::config::ResolverConfig,
+        trust_dns_resolver::config::ResolverOpts,
+    )> {
+        let opts = trust_dns_resolver::config::ResolverOpts::default();
+
+        if self.nameservers.is_empty() {
+            anyhow::ensure!(
+                self.protocol == ResolverProtocol::Udp,
+                "resolver protocol {:?} requires at least one explicit nameserver",
+                self.protocol
+            );
+            return Ok((trust_dns_resolver::config::ResolverConfig::default(), opts));
+        }
+
+        let protocol = match self.protocol {
+            ResolverProtocol::Udp => trust_dns_resolver::config::Protocol::Udp,
+            ResolverProtocol::Tcp => trust_dns_resolver::config::Protocol::Tcp,
+            ResolverProtocol::Https => trust_dns_resolver::config::Protocol::Https,
+        };
+
+        let name_servers = self
+            .nameservers
+            .iter()
+            .map(|addr| trust_dns_resolver::config::NameServerConfig {
+                socket_addr: *addr,
+                protocol,
+                tls_dns_name: self.tls_dns_name.clone(),
+                trust_nx_responses: false,
+            })
+            .collect();
+
+        Ok((
+            trust_dns_resolver::config::ResolverConfig::from_parts(None, vec![], name_servers),
+            opts,
+        ))
+    }
 }
 
 pub struct ClientBuilder {
@@ -32,11 +230,40 @@ pub struct ClientBuilder {
     pub body: Option<&'static [u8]>,
     pub tcp_nodelay: bool,
     pub timeout: Option<std::time::Duration>,
+    pub http_version: HttpVersion,
+    pub root_cert_store: RootCertStore,
+    /// Skip certificate verification entirely. Only ever meant for test servers.
+    pub insecure: bool,
+    /// ALPN protocols to advertise. Empty means "derive from `http_version`".
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Content-codings to advertise via `Accept-Encoding` and transparently decode
+    /// in the response. Empty disables the feature: bodies are measured as-is.
+    pub accept_encodings: Vec<Encoding>,
+    pub resolver_config: ResolverConfig,
+    /// Emit a PROXY protocol header right after the TCP handshake, before TLS or
+    /// any HTTP bytes, so `oha` looks like traffic arriving through a PROXY-protocol
+    /// aware L4 load balancer (HAProxy, an NLB, ...).
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Synthetic client addresses to rotate through in the PROXY header. Empty
+    /// falls back to the socket's real local address.
+    pub proxy_client_addrs: Vec<std::net::SocketAddr>,
+    /// Cache for the `TlsConnector` built from `root_cert_store`/`insecure`/ALPN,
+    /// so loading the root store (a disk read, for `File`/`Native`) happens once
+    /// per `ClientBuilder` rather than once per worker.
+    pub tls_connector: once_cell::sync::OnceCell<std::sync::Arc<tokio_rustls::TlsConnector>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// The human-readable text format, e.g. `PROXY TCP4 1.2.3.4 5.6.7.8 1234 443\r\n`.
+    V1,
+    /// The binary format, prefixed with the 12-byte PROXY v2 signature.
+    V2,
 }
 
 impl ClientBuilder {
-    pub fn build(&self) -> Client {
-        Client {
+    pub fn build(&self) -> anyhow::Result<Client> {
+        Ok(Client {
             url: self.url.clone(),
             method: self.method.clone(),
             headers: self.headers.clone(),
@@ -44,8 +271,77 @@ impl ClientBuilder {
             rng: rand::thread_rng(),
             resolver: None,
             send_request: None,
+            is_http2: false,
             tcp_nodelay: self.tcp_nodelay,
             timeout: self.timeout,
+            http_version: self.http_version,
+            tls_connector: self.build_tls_connector()?,
+            accept_encodings: self.accept_encodings.clone(),
+            resolver_config: self.resolver_config.clone(),
+            proxy_protocol: self.proxy_protocol,
+            proxy_client_addrs: self.proxy_client_addrs.clone(),
+        })
+    }
+
+    /// Builds the `TlsConnector` the first time it's needed and reuses it for
+    /// every subsequent worker, instead of re-reading/re-parsing the root store
+    /// from disk once per worker.
+    fn build_tls_connector(&self) -> anyhow::Result<std::sync::Arc<tokio_rustls::TlsConnector>> {
+        self.tls_connector
+            .get_or_try_init(|| -> anyhow::Result<_> {
+                let mut config = rustls::ClientConfig::new();
+
+                match &self.root_cert_store {
+                    RootCertStore::Native => {
+                        for cert in rustls_native_certs::load_native_certs()
+                            .context("load native certs")?
+                            .iter()
+                        {
+                            config
+                                .root_store
+                                .add(&rustls::Certificate(cert.0.clone()))
+                                .ok();
+                        }
+                    }
+                    RootCertStore::WebPki => {
+                        config
+                            .root_store
+                            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+                    }
+                    RootCertStore::File(path) => {
+                        let pem = std::fs::read(path)
+                            .with_context(|| format!("read CA file: {}", path.display()))?;
+                        let mut reader = std::io::BufReader::new(pem.as_slice());
+                        config
+                            .root_store
+                            .add_pem_file(&mut reader)
+                            .map_err(|()| anyhow::anyhow!("parse CA file: {}", path.display()))?;
+                    }
+                }
+
+                config.set_protocols(&self.alpn_protocols());
+
+                if self.insecure {
+                    config
+                        .dangerous()
+                        .set_certificate_verifier(std::sync::Arc::new(NoCertificateVerification));
+                }
+
+                Ok(std::sync::Arc::new(tokio_rustls::TlsConnector::from(
+                    std::sync::Arc::new(config),
+                )))
+            })
+            .map(Clone::clone)
+    }
+
+    fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        if !self.alpn_protocols.is_empty() {
+            return self.alpn_protocols.clone();
+        }
+        match self.http_version {
+            HttpVersion::Http1 => vec![b"http/1.1".to_vec()],
+            HttpVersion::Http2 => vec![b"h2".to_vec()],
+            HttpVersion::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
         }
     }
 }
@@ -64,17 +360,33 @@ pub struct Client {
             >,
         >,
     >,
-    send_request: Option<hyper::client::conn::SendRequest<hyper::Body>>,
+    send_request: Option<SendRequest>,
+    /// Whether the cached `send_request` above was negotiated as HTTP/2, and can
+    /// therefore be cloned to drive further concurrent streams on the same connection.
+    is_http2: bool,
     tcp_nodelay: bool,
     timeout: Option<std::time::Duration>,
+    http_version: HttpVersion,
+    tls_connector: std::sync::Arc<tokio_rustls::TlsConnector>,
+    accept_encodings: Vec<Encoding>,
+    resolver_config: ResolverConfig,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    proxy_client_addrs: Vec<std::net::SocketAddr>,
 }
 
 impl Client {
     async fn lookup_ip(&mut self) -> anyhow::Result<std::net::IpAddr> {
-        let resolver = if let Some(resolver) = self.resolver.take() {
+        let cached = if self.resolver_config.refresh_on_retry {
+            None
+        } else {
+            self.resolver.take()
+        };
+
+        let resolver = if let Some(resolver) = cached {
             resolver
         } else {
-            trust_dns_resolver::AsyncResolver::tokio(Default::default(), Default::default()).await?
+            let (config, opts) = self.resolver_config.to_trust_dns()?;
+            trust_dns_resolver::AsyncResolver::tokio(config, opts).await?
         };
 
         let addrs = resolver
@@ -85,35 +397,105 @@ impl Client {
 
         let addr = *addrs.choose(&mut self.rng).context("get addr")?;
 
-        self.resolver = Some(resolver);
+        if !self.resolver_config.refresh_on_retry {
+            self.resolver = Some(resolver);
+        }
 
         Ok(addr)
     }
 
-    async fn send_request(
+    /// Write a PROXY protocol header over `stream`, if configured. The advertised
+    /// source is picked from `proxy_client_addrs`, or is the socket's local address
+    /// when that list is empty; `dst` is advertised as the destination.
+    async fn write_proxy_protocol_header(
         &mut self,
-        addr: (std::net::IpAddr, u16),
-    ) -> anyhow::Result<hyper::client::conn::SendRequest<hyper::Body>> {
+        stream: &mut tokio::net::TcpStream,
+        dst: std::net::SocketAddr,
+    ) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let version = match self.proxy_protocol {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+
+        let src = if self.proxy_client_addrs.is_empty() {
+            stream.local_addr()?
+        } else {
+            *self
+                .proxy_client_addrs
+                .choose(&mut self.rng)
+                .context("pick a synthetic PROXY source address")?
+        };
+
+        let header = match version {
+            ProxyProtocolVersion::V1 => proxy_protocol_v1_header(src, dst)?.into_bytes(),
+            ProxyProtocolVersion::V2 => proxy_protocol_v2_header(src, dst)?,
+        };
+        stream.write_all(&header).await?;
+
+        Ok(())
+    }
+
+    async fn send_request(&mut self, addr: (std::net::IpAddr, u16)) -> anyhow::Result<SendRequest> {
         if self.url.scheme() == "https" {
-            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
             stream.set_nodelay(self.tcp_nodelay)?;
-            let connector = native_tls::TlsConnector::new()?;
-            let connector = tokio_tls::TlsConnector::from(connector);
-            let stream = connector
-                .connect(self.url.domain().context("get domain")?, stream)
+            self.write_proxy_protocol_header(&mut stream, addr.into())
+                .await?;
+            let domain =
+                webpki::DNSNameRef::try_from_ascii_str(self.url.domain().context("get domain")?)
+                    .context("invalid domain")?;
+            let stream = self.tls_connector.connect(domain, stream).await?;
+            let is_http2 = self.http_version == HttpVersion::Http2
+                || (self.http_version == HttpVersion::Auto
+                    && stream.get_ref().1.get_alpn_protocol() == Some(b"h2"));
+            self.is_http2 = is_http2;
+            let (send, conn) = hyper::client::conn::Builder::new()
+                .http2_only(is_http2)
+                .handshake(stream)
                 .await?;
-            let (send, conn) = hyper::client::conn::handshake(stream).await?;
             tokio::spawn(conn);
             Ok(send)
         } else {
-            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
             stream.set_nodelay(self.tcp_nodelay)?;
-            let (send, conn) = hyper::client::conn::handshake(stream).await?;
+            self.write_proxy_protocol_header(&mut stream, addr.into())
+                .await?;
+            // Plain TCP has no ALPN to negotiate over, so h2 here means "prior knowledge".
+            self.is_http2 = self.http_version == HttpVersion::Http2;
+            let (send, conn) = hyper::client::conn::Builder::new()
+                .http2_only(self.is_http2)
+                .handshake(stream)
+                .await?;
             tokio::spawn(conn);
             Ok(send)
         }
     }
 
+    /// A `SendRequest` for an HTTP/2 connection can be cloned to dispatch many
+    /// concurrent streams over the same underlying connection. Returns `None`
+    /// for HTTP/1.1, where a connection may only serve one request at a time.
+    pub fn clone_send_request(&self) -> Option<SendRequest> {
+        if self.is_http2 {
+            self.send_request.clone()
+        } else {
+            None
+        }
+    }
+
+    /// Establish the underlying connection (DNS lookup + TCP/TLS handshake) without
+    /// sending a request, so the negotiated `send_request` can be primed and shared
+    /// across workers before the benchmark starts.
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let addr = (
+            self.lookup_ip().await?,
+            self.url.port_or_known_default().context("get port")?,
+        );
+        self.send_request = Some(self.send_request(addr).await?);
+        Ok(())
+    }
+
     fn request(&self) -> anyhow::Result<http::Request<hyper::Body>> {
         let mut builder = http::Request::builder()
             .uri(http::uri::Uri::from_str(self.url.path())?)
@@ -124,6 +506,16 @@ impl Client {
             .context("get header")?
             .extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
 
+        if !self.accept_encodings.is_empty() {
+            let value = self
+                .accept_encodings
+                .iter()
+                .map(|e| e.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            builder = builder.header(http::header::ACCEPT_ENCODING, value);
+        }
+
         if let Some(body) = self.body {
             Ok(builder.body(hyper::Body::from(body))?)
         } else {
@@ -131,7 +523,15 @@ impl Client {
         }
     }
 
-    pub async fn work(&mut self) -> anyhow::Result<RequestResult> {
+    pub async fn work(
+        &mut self,
+        cancel: &mut tokio::sync::watch::Receiver<bool>,
+        intended_start: Option<std::time::Instant>,
+    ) -> anyhow::Result<RequestResult> {
+        if *cancel.borrow() {
+            anyhow::bail!("cancelled");
+        }
+
         let mut start = std::time::Instant::now();
         let mut send_request = if let Some(send_request) = self.send_request.take() {
             send_request
@@ -156,12 +556,30 @@ impl Client {
                     match res {
                         Ok(res) => {
                             let status = res.status();
-                            let mut len_sum = 0;
+                            let content_encoding = res
+                                .headers()
+                                .get(http::header::CONTENT_ENCODING)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(Encoding::from_header_value)
+                                .filter(|e| self.accept_encodings.contains(e));
 
                             let mut stream = res.into_body();
-                            while let Some(chunk) = stream.next().await {
-                                len_sum += chunk?.len();
-                            }
+                            let (len_sum, decompressed_bytes) = if let Some(encoding) =
+                                content_encoding
+                            {
+                                let mut body = Vec::new();
+                                while let Some(chunk) = stream.next().await {
+                                    body.extend_from_slice(&chunk?);
+                                }
+                                let len_sum = body.len();
+                                (len_sum, Some(encoding.decode(&body).await?.len()))
+                            } else {
+                                let mut len_sum = 0;
+                                while let Some(chunk) = stream.next().await {
+                                    len_sum += chunk?.len();
+                                }
+                                (len_sum, None)
+                            };
                             let end = std::time::Instant::now();
 
                             let result = RequestResult {
@@ -169,6 +587,8 @@ impl Client {
                                 end,
                                 status,
                                 len_bytes: len_sum,
+                                decompressed_bytes,
+                                intended_start,
                             };
 
                             self.send_request = Some(send_request);
@@ -197,6 +617,89 @@ impl Client {
     }
 }
 
+/// Build the PROXY protocol v1 text line, e.g. `PROXY TCP4 1.2.3.4 5.6.7.8 1234 443\r\n`.
+fn proxy_protocol_v1_header(
+    src: std::net::SocketAddr,
+    dst: std::net::SocketAddr,
+) -> anyhow::Result<String> {
+    let family = match (src, dst) {
+        (std::net::SocketAddr::V4(_), std::net::SocketAddr::V4(_)) => "TCP4",
+        (std::net::SocketAddr::V6(_), std::net::SocketAddr::V6(_)) => "TCP6",
+        _ => anyhow::bail!("PROXY protocol v1 requires matching address families"),
+    };
+    Ok(format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    ))
+}
+
+/// Build the binary PROXY protocol v2 header: the 12-byte signature, the
+/// version/command byte, the family/protocol byte, a 2-byte address-block
+/// length, then the address block itself.
+fn proxy_protocol_v2_header(
+    src: std::net::SocketAddr,
+    dst: std::net::SocketAddr,
+) -> anyhow::Result<Vec<u8>> {
+    let mut header = vec![
+        0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+    ];
+    header.push(0x21); // version 2, command PROXY
+
+    let (family_and_proto, addr_block) = match (src, dst) {
+        (std::net::SocketAddr::V4(src), std::net::SocketAddr::V4(dst)) => {
+            let mut block = Vec::with_capacity(12);
+            block.extend_from_slice(&src.ip().octets());
+            block.extend_from_slice(&dst.ip().octets());
+            block.extend_from_slice(&src.port().to_be_bytes());
+            block.extend_from_slice(&dst.port().to_be_bytes());
+            (0x11u8, block) // AF_INET, STREAM
+        }
+        (std::net::SocketAddr::V6(src), std::net::SocketAddr::V6(dst)) => {
+            let mut block = Vec::with_capacity(36);
+            block.extend_from_slice(&src.ip().octets());
+            block.extend_from_slice(&dst.ip().octets());
+            block.extend_from_slice(&src.port().to_be_bytes());
+            block.extend_from_slice(&dst.port().to_be_bytes());
+            (0x21u8, block) // AF_INET6, STREAM
+        }
+        _ => anyhow::bail!("PROXY protocol v2 requires matching address families"),
+    };
+
+    header.push(family_and_proto);
+    header.extend_from_slice(&(addr_block.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addr_block);
+
+    Ok(header)
+}
+
+/// When the client is configured for HTTP/2, open the connection once up front and
+/// hand back a `SendRequest` that every worker can clone to drive its own concurrent
+/// streams on it. Returns `None` for HTTP/1.1, where each worker owns its own connection.
+async fn prime_http2_connection(client_builder: &ClientBuilder) -> Option<SendRequest> {
+    if client_builder.http_version == HttpVersion::Http1 {
+        return None;
+    }
+    let mut seed = client_builder.build().ok()?;
+    seed.connect().await.ok()?;
+    seed.clone_send_request()
+}
+
+fn build_worker(
+    client_builder: &ClientBuilder,
+    shared: &Option<SendRequest>,
+) -> anyhow::Result<Client> {
+    let mut w = client_builder.build()?;
+    if let Some(send_request) = shared {
+        w.send_request = Some(send_request.clone());
+        w.is_http2 = true;
+    }
+    Ok(w)
+}
+
 /// Run n tasks by m workers
 /// Currently We use Fn() -> F as "task generator".
 /// Any replacement?
@@ -205,6 +708,7 @@ pub async fn work(
     report_tx: flume::Sender<anyhow::Result<RequestResult>>,
     n_tasks: usize,
     n_workers: usize,
+    cancel: tokio::sync::watch::Receiver<bool>,
 ) {
     let injector = crossbeam::deque::Injector::new();
 
@@ -212,41 +716,119 @@ pub async fn work(
         injector.push(());
     }
 
-    futures::future::join_all((0..n_workers).map(|_| async {
-        let mut w = client_builder.build();
-        while let crossbeam::deque::Steal::Success(()) = injector.steal() {
-            report_tx.send(w.work().await).unwrap();
+    let shared = prime_http2_connection(&client_builder).await;
+
+    futures::future::join_all((0..n_workers).map(|_| {
+        let mut cancel = cancel.clone();
+        let client_builder = &client_builder;
+        let shared = &shared;
+        let injector = &injector;
+        let report_tx = &report_tx;
+        async move {
+            let mut w = match build_worker(client_builder, shared) {
+                Ok(w) => w,
+                Err(e) => {
+                    report_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            while !*cancel.borrow() {
+                match injector.steal() {
+                    crossbeam::deque::Steal::Success(()) => {
+                        report_tx.send(w.work(&mut cancel, None).await).unwrap();
+                    }
+                    _ => break,
+                }
+            }
         }
     }))
     .await;
 }
 
+/// The intended arrival time of the `i`-th request: `start + i/qps` on a fixed
+/// grid (so the first one is `start` itself), or `prev` plus an
+/// exponentially-distributed gap for a Poisson arrival process.
+fn next_arrival(
+    distribution: ArrivalDistribution,
+    start: std::time::Instant,
+    prev: std::time::Instant,
+    i: usize,
+    qps: usize,
+) -> std::time::Instant {
+    match distribution {
+        ArrivalDistribution::Fixed => {
+            start + i as u32 * std::time::Duration::from_secs(1) / qps as u32
+        }
+        ArrivalDistribution::Poisson => {
+            // Inverse-CDF sampling of an exponential distribution: gap = -ln(U)/qps.
+            let u: f64 = rand::thread_rng().gen_range(f64::MIN_POSITIVE, 1.0);
+            prev + std::time::Duration::from_secs_f64(-u.ln() / qps as f64)
+        }
+    }
+}
+
 /// n tasks by m workers limit to qps works in a second
+///
+/// Arrivals are scheduled open-loop: the pacer decides every request's intended
+/// start time up front and hands it to whichever worker becomes free, instead of
+/// waiting for a worker before deciding the next arrival. This avoids coordinated
+/// omission, where a slow server would otherwise understate the latency clients
+/// actually observe.
 pub async fn work_with_qps(
     client_builder: ClientBuilder,
     report_tx: flume::Sender<anyhow::Result<RequestResult>>,
     qps: usize,
     n_tasks: usize,
     n_workers: usize,
+    cancel: tokio::sync::watch::Receiver<bool>,
+    distribution: ArrivalDistribution,
 ) {
     let (tx, rx) = crossbeam::channel::unbounded();
 
+    let mut gen_cancel = cancel.clone();
     tokio::spawn(async move {
         let start = std::time::Instant::now();
+        let mut intended_start = start;
         for i in 0..n_tasks {
-            tx.send(()).unwrap();
-            tokio::time::delay_until(
-                (start + i as u32 * std::time::Duration::from_secs(1) / qps as u32).into(),
-            )
-            .await;
+            if *gen_cancel.borrow() {
+                break;
+            }
+            intended_start = next_arrival(distribution, start, intended_start, i, qps);
+            tokio::select! {
+                _ = tokio::time::delay_until(intended_start.into()) => {}
+                _ = gen_cancel.changed() => { break; }
+            }
+            if tx.send(intended_start).is_err() {
+                break;
+            }
         }
         // tx gone
     });
 
-    futures::future::join_all((0..n_workers).map(|_| async {
-        let mut w = client_builder.build();
-        while let Ok(()) = rx.recv() {
-            report_tx.send(w.work().await).unwrap();
+    let shared = prime_http2_connection(&client_builder).await;
+
+    futures::future::join_all((0..n_workers).map(|_| {
+        let mut cancel = cancel.clone();
+        let client_builder = &client_builder;
+        let shared = &shared;
+        let rx = &rx;
+        let report_tx = &report_tx;
+        async move {
+            let mut w = match build_worker(client_builder, shared) {
+                Ok(w) => w,
+                Err(e) => {
+                    report_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            while let Ok(intended_start) = rx.recv() {
+                if *cancel.borrow() {
+                    break;
+                }
+                report_tx
+                    .send(w.work(&mut cancel, Some(intended_start)).await)
+                    .unwrap();
+            }
         }
     }))
     .await;
@@ -258,11 +840,26 @@ pub async fn work_until(
     report_tx: flume::Sender<anyhow::Result<RequestResult>>,
     dead_line: std::time::Instant,
     n_workers: usize,
+    cancel: tokio::sync::watch::Receiver<bool>,
 ) {
-    futures::future::join_all((0..n_workers).map(|_| async {
-        let mut w = client_builder.build();
-        while std::time::Instant::now() < dead_line {
-            report_tx.send(w.work().await).unwrap();
+    let shared = prime_http2_connection(&client_builder).await;
+
+    futures::future::join_all((0..n_workers).map(|_| {
+        let mut cancel = cancel.clone();
+        let client_builder = &client_builder;
+        let shared = &shared;
+        let report_tx = &report_tx;
+        async move {
+            let mut w = match build_worker(client_builder, shared) {
+                Ok(w) => w,
+                Err(e) => {
+                    report_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            while std::time::Instant::now() < dead_line && !*cancel.borrow() {
+                report_tx.send(w.work(&mut cancel, None).await).unwrap();
+            }
         }
     }))
     .await;
@@ -276,35 +873,140 @@ pub async fn work_until_with_qps(
     start: std::time::Instant,
     dead_line: std::time::Instant,
     n_workers: usize,
+    cancel: tokio::sync::watch::Receiver<bool>,
+    distribution: ArrivalDistribution,
 ) {
-    let (tx, rx) = crossbeam::channel::bounded(qps);
+    let (tx, rx) = crossbeam::channel::unbounded();
 
+    let mut gen_cancel = cancel.clone();
     let gen = tokio::spawn(async move {
+        let mut intended_start = start;
         for i in 0.. {
-            if std::time::Instant::now() > dead_line {
+            if std::time::Instant::now() > dead_line || *gen_cancel.borrow() {
+                break;
+            }
+            intended_start = next_arrival(distribution, start, intended_start, i, qps);
+            if intended_start > dead_line {
                 break;
             }
-            if tx.send(()).is_err() {
+            tokio::select! {
+                _ = tokio::time::delay_until(intended_start.into()) => {}
+                _ = gen_cancel.changed() => { break; }
+            }
+            if tx.send(intended_start).is_err() {
                 break;
             }
-            tokio::time::delay_until(
-                (start + i as u32 * std::time::Duration::from_secs(1) / qps as u32).into(),
-            )
-            .await;
         }
         // tx gone
     });
 
-    futures::future::join_all((0..n_workers).map(|_| async {
-        let mut w = client_builder.build();
-        while let Ok(()) = rx.recv() {
-            if std::time::Instant::now() > dead_line {
-                break;
+    let shared = prime_http2_connection(&client_builder).await;
+
+    futures::future::join_all((0..n_workers).map(|_| {
+        let mut cancel = cancel.clone();
+        let client_builder = &client_builder;
+        let shared = &shared;
+        let rx = &rx;
+        let report_tx = &report_tx;
+        async move {
+            let mut w = match build_worker(client_builder, shared) {
+                Ok(w) => w,
+                Err(e) => {
+                    report_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            while let Ok(intended_start) = rx.recv() {
+                if std::time::Instant::now() > dead_line || *cancel.borrow() {
+                    break;
+                }
+                report_tx
+                    .send(w.work(&mut cancel, Some(intended_start)).await)
+                    .unwrap();
             }
-            report_tx.send(w.work().await).unwrap();
         }
     }))
     .await;
 
     let _ = gen.await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> std::net::SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn proxy_v1_header_v4() {
+        let header = proxy_protocol_v1_header(addr("1.2.3.4:1234"), addr("5.6.7.8:443")).unwrap();
+        assert_eq!(header, "PROXY TCP4 1.2.3.4 5.6.7.8 1234 443\r\n");
+    }
+
+    #[test]
+    fn proxy_v1_header_v6() {
+        let header = proxy_protocol_v1_header(addr("[::1]:1234"), addr("[::2]:443")).unwrap();
+        assert_eq!(header, "PROXY TCP6 ::1 ::2 1234 443\r\n");
+    }
+
+    #[test]
+    fn proxy_v1_header_rejects_mismatched_families() {
+        assert!(proxy_protocol_v1_header(addr("1.2.3.4:1234"), addr("[::2]:443")).is_err());
+    }
+
+    #[test]
+    fn proxy_v2_header_v4() {
+        let header = proxy_protocol_v2_header(addr("1.2.3.4:1234"), addr("5.6.7.8:443")).unwrap();
+        assert_eq!(
+            header,
+            vec![
+                0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A, // sig
+                0x21, // version 2, command PROXY
+                0x11, // AF_INET, STREAM
+                0x00, 0x0C, // address-block length: 12
+                1, 2, 3, 4, // src ip
+                5, 6, 7, 8, // dst ip
+                0x04, 0xD2, // src port 1234
+                0x01, 0xBB, // dst port 443
+            ]
+        );
+    }
+
+    #[test]
+    fn proxy_v2_header_rejects_mismatched_families() {
+        assert!(proxy_protocol_v2_header(addr("1.2.3.4:1234"), addr("[::2]:443")).is_err());
+    }
+
+    #[test]
+    fn next_arrival_fixed_advances_on_a_grid() {
+        let start = std::time::Instant::now();
+        let first = next_arrival(ArrivalDistribution::Fixed, start, start, 0, 10);
+        let second = next_arrival(ArrivalDistribution::Fixed, start, start, 1, 10);
+        assert_eq!(first, start);
+        assert_eq!(second - start, std::time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn next_arrival_poisson_always_advances() {
+        let start = std::time::Instant::now();
+        let next = next_arrival(ArrivalDistribution::Poisson, start, start, 0, 10);
+        assert!(next > start);
+    }
+
+    #[test]
+    fn encoding_header_value_round_trips() {
+        for encoding in [Encoding::Gzip, Encoding::Brotli, Encoding::Deflate] {
+            assert_eq!(
+                Encoding::from_header_value(encoding.as_str()),
+                Some(encoding)
+            );
+        }
+    }
+
+    #[test]
+    fn encoding_from_header_value_rejects_unknown() {
+        assert_eq!(Encoding::from_header_value("identity"), None);
+    }
+}